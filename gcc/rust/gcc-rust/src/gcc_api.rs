@@ -486,6 +486,92 @@ impl Tree {
         unsafe { _build1(TreeCode::ReturnExpr, TreeIndex::VoidType.into(), value) }
     }
 
+    pub fn new_goto_expr(label: Tree) -> Self {
+        unsafe { _build1(TreeCode::GotoExpr, TreeIndex::VoidType.into(), label) }
+    }
+
+    pub fn new_cond_expr(cond: Tree, then_clause: Tree, else_clause: Tree) -> Self {
+        unsafe {
+            _build3(
+                TreeCode::CondExpr,
+                TreeIndex::VoidType.into(),
+                cond,
+                then_clause,
+                else_clause,
+            )
+        }
+    }
+
+    pub fn new_save_expr(type_: Tree, value: Tree) -> Self {
+        unsafe { _build1(TreeCode::SaveExpr, type_, value) }
+    }
+
+    pub fn new_binary_expr(code: TreeCode, result_type: Tree, lhs: Tree, rhs: Tree) -> Self {
+        unsafe { _build2(code, result_type, lhs, rhs) }
+    }
+
+    pub fn new_unary_expr(code: TreeCode, result_type: Tree, value: Tree) -> Self {
+        unsafe { _build1(code, result_type, value) }
+    }
+
+    pub fn new_var_decl(loc: Location, type_: Tree) -> Self {
+        unsafe { _build_decl(loc, TreeCode::VarDecl, NULL_TREE, type_) }
+    }
+
+    pub fn new_tree_list(value: Tree, chain: Tree) -> Self {
+        unsafe { _tree_cons(NULL_TREE, value, chain) }
+    }
+
+    pub fn new_pointer_type(to: Tree) -> Self {
+        unsafe { _build_pointer_type(to) }
+    }
+
+    pub fn set_restrict(self, value: bool) {
+        unsafe { set_decl_restrict(self, value) }
+    }
+
+    pub fn new_field_decl(loc: Location, name: Tree, type_: Tree) -> Self {
+        unsafe { _build_decl(loc, TreeCode::FieldDecl, name, type_) }
+    }
+
+    pub fn new_record_type(fields: &[Tree]) -> Self {
+        unsafe {
+            let record_type = _build_record_type();
+            for &field in fields {
+                _add_record_field(record_type, field);
+            }
+            _finish_record_type(record_type);
+            record_type
+        }
+    }
+
+    // TREE_TYPE of `self` -- the type of a decl/expr, or the pointee type of a pointer type.
+    pub fn ty(self) -> Self {
+        unsafe { _tree_type(self) }
+    }
+
+    pub fn record_field(self, index: usize) -> Self {
+        unsafe { _record_type_field(self, index) }
+    }
+
+    pub fn new_indirect_ref(pointer: Tree) -> Self {
+        let pointee_type = pointer.ty().ty();
+        unsafe { _build1(TreeCode::IndirectRef, pointee_type, pointer) }
+    }
+
+    pub fn new_component_ref(base: Tree, field: Tree) -> Self {
+        unsafe { _build3(TreeCode::ComponentRef, field.ty(), base, field, NULL_TREE) }
+    }
+
+    pub fn new_addr_expr(value: Tree) -> Self {
+        let pointer_type = Tree::new_pointer_type(value.ty());
+        unsafe { _build1(TreeCode::AddrExpr, pointer_type, value) }
+    }
+
+    pub fn new_call_expr(loc: Location, return_type: Tree, fn_ptr: Tree, args: &[Tree]) -> Self {
+        unsafe { _build_call_array_loc(loc, return_type, fn_ptr, args.len(), args.as_ptr()) }
+    }
+
     pub fn new_block(vars: Tree, subblocks: Tree, supercontext: Tree, chain: Tree) -> Self {
         unsafe { _build_block(vars, subblocks, supercontext, chain) }
     }
@@ -554,6 +640,7 @@ extern "C" {
     ) -> Tree;
     fn _build_fn_decl(name: *const c_char, decltype: Tree) -> Tree;
     fn _create_artificial_label(loc: Location) -> Tree;
+    fn _tree_cons(purpose: Tree, value: Tree, chain: Tree) -> Tree;
     fn _gimplify_function_tree(tree: Tree);
 
     fn build_int_constant(inttype: Tree, value: i64) -> Tree;
@@ -562,6 +649,12 @@ extern "C" {
     fn set_fn_saved_tree(fn_decl: Tree, tree: Tree);
     fn set_fn_external(fn_decl: Tree, value: bool);
     fn set_fn_preserve_p(fn_decl: Tree, value: bool);
+    fn set_decl_restrict(decl: Tree, value: bool);
+    fn _build_record_type() -> Tree;
+    fn _add_record_field(record_type: Tree, field: Tree);
+    fn _finish_record_type(record_type: Tree);
+    fn _tree_type(node: Tree) -> Tree;
+    fn _record_type_field(record_type: Tree, index: usize) -> Tree;
     fn add_fn_parm_decls(fn_decl: Tree, num_args: usize, arg_types: *const Tree, decls: *mut Tree);
     fn finalize_decl(tree: Tree);
     fn finalize_function(tree: Tree, no_collect: bool);
@@ -1,18 +1,102 @@
 use crate::gcc_api::*;
 use rustc::{
-    hir::def_id::LOCAL_CRATE,
+    hir::{
+        def_id::{DefId, LOCAL_CRATE},
+        Mutability,
+    },
     mir::{
         interpret::{ConstValue, Scalar},
-        BasicBlock, BasicBlockData, Body, Operand, Place, PlaceBase, Rvalue, StatementKind,
-        TerminatorKind,
+        BasicBlock, BasicBlockData, BinOp, Body, NullOp, Operand, Place, PlaceBase, ProjectionElem,
+        Rvalue, StatementKind, TerminatorKind, UnOp,
     },
-    ty::{ConstKind, Ty, TyKind},
+    ty::{ConstKind, ParamEnv, Ty, TyCtxt, TyKind},
 };
 use rustc_interface::Queries;
-use std::{convert::TryInto, ffi::CString};
+use std::{collections::HashMap, convert::TryInto, ffi::CString};
 use syntax::ast::{IntTy, UintTy};
 use syntax_pos::symbol::Symbol;
 
+// Maps each local function to the `FUNCTION_DECL` created for it, so that a call appearing
+// before its callee's body has been converted reuses the same decl (marked external until the
+// callee is actually defined) instead of creating a duplicate.
+struct FunctionTable<'tcx> {
+    tcx: TyCtxt<'tcx>,
+    functions: HashMap<DefId, Tree>,
+    alloc_fn: Option<Tree>,
+    free_fn: Option<Tree>,
+}
+
+impl<'tcx> FunctionTable<'tcx> {
+    fn new(tcx: TyCtxt<'tcx>) -> Self {
+        Self {
+            tcx,
+            functions: HashMap::new(),
+            alloc_fn: None,
+            free_fn: None,
+        }
+    }
+
+    fn declare(&mut self, def_id: DefId) -> Tree {
+        if let Some(&decl) = self.functions.get(&def_id) {
+            return decl;
+        }
+
+        let (return_type, arg_types) = if self.tcx.is_mir_available(def_id) {
+            let body = self.tcx.optimized_mir(def_id);
+            (
+                make_function_return_type(body),
+                make_function_arg_types(body),
+            )
+        } else {
+            // Genuinely external (an `extern "C"` declaration, or a non-generic/non-`#[inline]`
+            // item from another crate): no MIR is cached for it, so build the decl from its
+            // signature alone rather than calling `optimized_mir`.
+            let sig = self.tcx.fn_sig(def_id).skip_binder();
+            let return_type = convert_type(sig.output());
+            let arg_types: Vec<Tree> = sig.inputs().iter().map(|&ty| convert_type(ty)).collect();
+            (return_type, arg_types)
+        };
+        let fn_type = Tree::new_function_type(return_type, &arg_types);
+
+        let name = CString::new(&*self.tcx.item_name(def_id).as_str()).unwrap();
+        let mut fn_decl = Function::new(&name, fn_type);
+        fn_decl.set_external(true);
+
+        self.functions.insert(def_id, fn_decl.0);
+        fn_decl.0
+    }
+
+    // `void *malloc(size_t)`, declared lazily the first time a `Box::new` needs it.
+    fn alloc_fn(&mut self) -> Tree {
+        if let Some(decl) = self.alloc_fn {
+            return decl;
+        }
+
+        let fn_type =
+            Tree::new_function_type(TreeIndex::PtrType.into(), &[TreeIndex::SizeType.into()]);
+        let mut fn_decl = Function::new(&CString::new("malloc").unwrap(), fn_type);
+        fn_decl.set_external(true);
+
+        self.alloc_fn = Some(fn_decl.0);
+        fn_decl.0
+    }
+
+    // `void free(void *)`, declared lazily the first time a boxed value is dropped.
+    fn free_fn(&mut self) -> Tree {
+        if let Some(decl) = self.free_fn {
+            return decl;
+        }
+
+        let fn_type =
+            Tree::new_function_type(TreeIndex::VoidType.into(), &[TreeIndex::PtrType.into()]);
+        let mut fn_decl = Function::new(&CString::new("free").unwrap(), fn_type);
+        fn_decl.set_external(true);
+
+        self.free_fn = Some(fn_decl.0);
+        fn_decl.0
+    }
+}
+
 fn convert_type(ty: Ty) -> Tree {
     use TyKind::*;
 
@@ -29,10 +113,93 @@ fn convert_type(ty: Ty) -> Tree {
         Uint(UintTy::U16) => IntegerTypeKind::UnsignedShort.into(),
         Uint(UintTy::U32) => IntegerTypeKind::UnsignedInt.into(),
         Uint(UintTy::U64) => IntegerTypeKind::UnsignedLongLong.into(),
+        Bool => TreeIndex::BooleanType.into(),
+        Ref(_, pointee, _) => convert_pointer_type(pointee),
+        RawPtr(type_and_mut) => convert_pointer_type(type_and_mut.ty),
+        _ if ty.is_box() => Tree::new_pointer_type(convert_type(ty.boxed_ty())),
         _ => unimplemented!("type: {:?}", ty),
     }
 }
 
+// `&T`/`*T` lower to a thin pointer, except for unsized `T` (slices, `str`, trait objects),
+// which need a fat pointer: a data pointer plus a metadata word. Field order and sizes here
+// must match rustc's layout so ABI-compatible calls work.
+fn convert_pointer_type(pointee: Ty) -> Tree {
+    use TyKind::*;
+
+    match pointee.kind {
+        Slice(elem) => Tree::new_record_type(&[
+            Tree::new_field_decl(
+                UNKNOWN_LOCATION,
+                NULL_TREE,
+                Tree::new_pointer_type(convert_type(elem)),
+            ),
+            Tree::new_field_decl(
+                UNKNOWN_LOCATION,
+                NULL_TREE,
+                IntegerTypeKind::UnsignedLong.into(),
+            ),
+        ]),
+
+        Str => Tree::new_record_type(&[
+            Tree::new_field_decl(
+                UNKNOWN_LOCATION,
+                NULL_TREE,
+                Tree::new_pointer_type(IntegerTypeKind::UnsignedChar.into()),
+            ),
+            Tree::new_field_decl(
+                UNKNOWN_LOCATION,
+                NULL_TREE,
+                IntegerTypeKind::UnsignedLong.into(),
+            ),
+        ]),
+
+        Dynamic(..) => Tree::new_record_type(&[
+            Tree::new_field_decl(
+                UNKNOWN_LOCATION,
+                NULL_TREE,
+                Tree::new_pointer_type(TreeIndex::VoidType.into()),
+            ),
+            Tree::new_field_decl(
+                UNKNOWN_LOCATION,
+                NULL_TREE,
+                Tree::new_pointer_type(TreeIndex::VoidType.into()),
+            ),
+        ]),
+
+        _ => Tree::new_pointer_type(convert_type(pointee)),
+    }
+}
+
+fn make_function_arg_is_unique_ref(body: &Body) -> Vec<bool> {
+    body.args_iter()
+        .map(|arg| match body.local_decls[arg].ty.kind {
+            TyKind::Ref(_, _, Mutability::MutMutable) => true,
+            _ => false,
+        })
+        .collect()
+}
+
+fn operand_ty<'tcx>(body: &Body<'tcx>, op: &Operand<'tcx>) -> Ty<'tcx> {
+    match op {
+        Operand::Copy(place) | Operand::Move(place) => match &place.base {
+            PlaceBase::Local(local) => body.local_decls[*local].ty,
+            _ => unimplemented!("operand base {:?}", place),
+        },
+        Operand::Constant(c) => c.literal.ty,
+    }
+}
+
+fn place_ty<'tcx>(body: &Body<'tcx>, place: &Place<'tcx>) -> Ty<'tcx> {
+    if !place.projection.is_empty() {
+        unimplemented!("place type through projection {:?}", place);
+    }
+    match &place.base {
+        PlaceBase::Local(local) => body.local_decls[*local].ty,
+        _ => unimplemented!("place base {:?}", place),
+    }
+}
+
 fn make_function_return_type(body: &Body) -> Tree {
     convert_type(body.return_ty())
 }
@@ -51,18 +218,27 @@ struct FunctionConversion {
     block_labels: Vec<Tree>,
     main_gcc_block: Tree,
     stmt_list: StatementList,
+    // Goto target for a call whose callee never returns (no destination block), so the
+    // flattened statement list never falls through into the next block's label.
+    unreachable_label: Tree,
 }
 
 impl FunctionConversion {
-    fn new(name: Symbol, body: &Body) -> Self {
+    fn new(table: &mut FunctionTable, def_id: DefId, name: Symbol, body: &Body) -> Self {
         let return_type = make_function_return_type(body);
         let arg_types = make_function_arg_types(body);
         let fn_type = Tree::new_function_type(return_type, &arg_types);
 
-        let name = CString::new(&*name.as_str()).unwrap();
-        let mut fn_decl = Function::new(&name, fn_type);
+        let mut fn_decl = match table.functions.get(&def_id) {
+            Some(&decl) => Function(decl),
+            None => {
+                let name = CString::new(&*name.as_str()).unwrap();
+                Function::new(&name, fn_type)
+            }
+        };
         fn_decl.set_external(false);
         fn_decl.set_preserve_p(true);
+        table.functions.insert(def_id, fn_decl.0);
 
         let main_gcc_block = Tree::new_block(NULL_TREE, NULL_TREE, fn_decl.0, NULL_TREE);
         fn_decl.set_initial(main_gcc_block);
@@ -72,7 +248,26 @@ impl FunctionConversion {
 
         let parm_decls = fn_decl.add_parm_decls(&arg_types);
 
-        let vars = vec![];
+        // `&mut T` is provably non-aliasing (the borrow checker already proved it, the
+        // same guarantee LLVM encodes as `noalias`), so distinct `&mut` parameters can
+        // be marked restrict-style for GCC's alias analysis. This must stay suppressed
+        // once unwinding terminators exist, since a store only observable on the unwind
+        // path must not be dropped as redundant.
+        for (&decl, is_unique) in parm_decls.iter().zip(make_function_arg_is_unique_ref(body)) {
+            if is_unique {
+                decl.set_restrict(true);
+            }
+        }
+
+        let vars = body
+            .local_decls
+            .indices()
+            .filter(|local| local.as_usize() > arg_types.len())
+            .map(|local| {
+                let ty = convert_type(body.local_decls[local].ty);
+                Tree::new_var_decl(UNKNOWN_LOCATION, ty)
+            })
+            .collect::<Vec<_>>();
 
         let block_labels = body
             .basic_blocks()
@@ -81,6 +276,7 @@ impl FunctionConversion {
             .collect::<Vec<_>>();
 
         let stmt_list = StatementList::new();
+        let unreachable_label = Tree::new_artificial_label(UNKNOWN_LOCATION);
 
         Self {
             fn_decl,
@@ -90,15 +286,12 @@ impl FunctionConversion {
             block_labels,
             main_gcc_block,
             stmt_list,
+            unreachable_label,
         }
     }
 
     fn get_place(&self, place: &Place) -> Tree {
-        if !place.projection.is_empty() {
-            unimplemented!("non-empty projection");
-        }
-
-        match &place.base {
+        let base = match &place.base {
             PlaceBase::Local(local) => {
                 let n = local.as_usize();
                 if n == 0 {
@@ -106,25 +299,31 @@ impl FunctionConversion {
                 } else if n <= self.parm_decls.len() {
                     self.parm_decls[n - 1]
                 } else {
-                    unimplemented!("place base {}", n)
+                    self.vars[n - self.parm_decls.len() - 1]
                 }
             }
 
             _ => unimplemented!("base {:?}", place),
-        }
+        };
+
+        place.projection.iter().fold(base, |tree, elem| match elem {
+            ProjectionElem::Deref => Tree::new_indirect_ref(tree),
+            ProjectionElem::Field(field, _ty) => {
+                Tree::new_component_ref(tree, tree.ty().record_field(field.index()))
+            }
+            _ => unimplemented!("projection {:?}", elem),
+        })
     }
 
-    fn convert_rvalue(&self, rv: &Rvalue) -> Tree {
+    fn convert_operand(&self, op: &Operand) -> Tree {
         use ConstKind::*;
         use Operand::*;
-        use Rvalue::*;
         use TyKind::*;
 
-        match rv {
-            Use(Copy(place)) => self.get_place(place),
-            Use(Move(place)) => self.get_place(place),
+        match op {
+            Copy(place) | Move(place) => self.get_place(place),
 
-            Use(Constant(c)) => {
+            Constant(c) => {
                 let lit = &c.literal;
 
                 match &lit.val {
@@ -139,12 +338,122 @@ impl FunctionConversion {
                     _ => unimplemented!("const {:?} {:?}", lit.ty, lit.val),
                 }
             }
+        }
+    }
+
+    fn convert_rvalue(&self, table: &mut FunctionTable, body: &Body, rv: &Rvalue) -> Tree {
+        use BinOp::*;
+        use Rvalue::*;
+        use UnOp::*;
+
+        match rv {
+            Use(op) => self.convert_operand(op),
+
+            NullaryOp(NullOp::Box, ty) => {
+                let size = table
+                    .tcx
+                    .layout_of(ParamEnv::reveal_all().and(*ty))
+                    .unwrap()
+                    .size
+                    .bytes();
+                let size_const = Tree::new_int_constant(TreeIndex::SizeType.into(), size as i64);
+                let alloc_fn = table.alloc_fn();
+
+                Tree::new_call_expr(
+                    UNKNOWN_LOCATION,
+                    TreeIndex::PtrType.into(),
+                    Tree::new_addr_expr(alloc_fn),
+                    &[size_const],
+                )
+            }
+
+            BinaryOp(op, ops) => {
+                let (lhs, rhs) = &**ops;
+                let operand_type = convert_type(operand_ty(body, lhs));
+                let lhs = self.convert_operand(lhs);
+                let rhs = self.convert_operand(rhs);
+
+                match op {
+                    Add => Tree::new_binary_expr(TreeCode::PlusExpr, operand_type, lhs, rhs),
+                    Sub => Tree::new_binary_expr(TreeCode::MinusExpr, operand_type, lhs, rhs),
+                    Mul => Tree::new_binary_expr(TreeCode::MultExpr, operand_type, lhs, rhs),
+                    Div => Tree::new_binary_expr(TreeCode::TruncDivExpr, operand_type, lhs, rhs),
+                    Rem => Tree::new_binary_expr(TreeCode::TruncModExpr, operand_type, lhs, rhs),
+                    BitXor => Tree::new_binary_expr(TreeCode::BitXorExpr, operand_type, lhs, rhs),
+                    BitAnd => Tree::new_binary_expr(TreeCode::BitAndExpr, operand_type, lhs, rhs),
+                    BitOr => Tree::new_binary_expr(TreeCode::BitIorExpr, operand_type, lhs, rhs),
+                    Shl => Tree::new_binary_expr(TreeCode::LshiftExpr, operand_type, lhs, rhs),
+                    Shr => Tree::new_binary_expr(TreeCode::RshiftExpr, operand_type, lhs, rhs),
+
+                    Lt => Tree::new_binary_expr(
+                        TreeCode::LtExpr,
+                        TreeIndex::BooleanType.into(),
+                        lhs,
+                        rhs,
+                    ),
+                    Le => Tree::new_binary_expr(
+                        TreeCode::LeExpr,
+                        TreeIndex::BooleanType.into(),
+                        lhs,
+                        rhs,
+                    ),
+                    Gt => Tree::new_binary_expr(
+                        TreeCode::GtExpr,
+                        TreeIndex::BooleanType.into(),
+                        lhs,
+                        rhs,
+                    ),
+                    Ge => Tree::new_binary_expr(
+                        TreeCode::GeExpr,
+                        TreeIndex::BooleanType.into(),
+                        lhs,
+                        rhs,
+                    ),
+                    Eq => Tree::new_binary_expr(
+                        TreeCode::EqExpr,
+                        TreeIndex::BooleanType.into(),
+                        lhs,
+                        rhs,
+                    ),
+                    Ne => Tree::new_binary_expr(
+                        TreeCode::NeExpr,
+                        TreeIndex::BooleanType.into(),
+                        lhs,
+                        rhs,
+                    ),
+
+                    Offset => unimplemented!("pointer offset"),
+                }
+            }
+
+            UnaryOp(op, operand) => {
+                let ty = operand_ty(body, operand);
+                let operand_type = convert_type(ty);
+                let value = self.convert_operand(operand);
+
+                match op {
+                    Neg => Tree::new_unary_expr(TreeCode::NegateExpr, operand_type, value),
+                    // MIR's `Not` covers both bitwise-not on integers and logical-not on
+                    // `bool`; bitwise-complementing a 0/1-valued boolean would flip bits
+                    // outside the represented range instead of negating its truth value.
+                    Not if ty.is_bool() => {
+                        Tree::new_unary_expr(TreeCode::TruthNotExpr, operand_type, value)
+                    }
+                    Not => Tree::new_unary_expr(TreeCode::BitNotExpr, operand_type, value),
+                }
+            }
 
             _ => unimplemented!("rvalue {:?}", rv),
         }
     }
 
-    fn convert_basic_block(&mut self, block_index: BasicBlock, block: &BasicBlockData) {
+    fn convert_basic_block(
+        &mut self,
+        table: &mut FunctionTable,
+        body: &Body,
+        block_index: BasicBlock,
+        block: &BasicBlockData,
+    ) {
         println!("{:?}", block);
 
         self.stmt_list
@@ -152,6 +461,7 @@ impl FunctionConversion {
 
         use StatementKind::*;
         use TerminatorKind::*;
+        use TyKind::*;
 
         for stmt in &block.statements {
             match &stmt.kind {
@@ -162,7 +472,7 @@ impl FunctionConversion {
                     eprintln!("{:?} = {:?}", place, rvalue);
 
                     let place = self.get_place(place);
-                    let rvalue = self.convert_rvalue(rvalue);
+                    let rvalue = self.convert_rvalue(table, body, rvalue);
                     self.stmt_list.push(Tree::new_init_expr(place, rvalue));
                 }
                 _ => unimplemented!("{:?}", stmt),
@@ -175,12 +485,129 @@ impl FunctionConversion {
                 self.stmt_list.push(Tree::new_return_expr(self.res_decl));
             }
 
+            Goto { target } => {
+                self.stmt_list
+                    .push(Tree::new_goto_expr(self.block_labels[target.as_usize()]));
+            }
+
+            SwitchInt {
+                discr,
+                values,
+                targets,
+                ..
+            } => {
+                let discr_type = convert_type(operand_ty(body, discr));
+                let discr_value = Tree::new_save_expr(discr_type, self.convert_operand(discr));
+
+                for (value, target) in values.iter().zip(targets) {
+                    // `value` is a u128 (MIR represents every discriminant width this way),
+                    // but `new_int_constant` only takes the HOST_WIDE_INT bit pattern; a
+                    // discriminant whose value doesn't fit `i64` (e.g. a `u64` match arm
+                    // >= 2^63) must still reinterpret its low 64 bits rather than panic.
+                    let case_value = Tree::new_int_constant(discr_type, *value as i64);
+                    let eq = Tree::new_binary_expr(
+                        TreeCode::EqExpr,
+                        TreeIndex::BooleanType.into(),
+                        discr_value,
+                        case_value,
+                    );
+                    let goto = Tree::new_goto_expr(self.block_labels[target.as_usize()]);
+
+                    self.stmt_list
+                        .push(Tree::new_cond_expr(eq, goto, NULL_TREE));
+                }
+
+                let otherwise = targets.last().unwrap();
+                self.stmt_list
+                    .push(Tree::new_goto_expr(self.block_labels[otherwise.as_usize()]));
+            }
+
+            Call {
+                func,
+                args,
+                destination,
+                ..
+            } => {
+                let def_id = match func {
+                    Operand::Constant(c) => match c.literal.ty.kind {
+                        FnDef(def_id, _) => def_id,
+                        _ => unimplemented!("call to non-function-item operand {:?}", func),
+                    },
+                    _ => unimplemented!("indirect call {:?}", func),
+                };
+
+                let callee = table.declare(def_id);
+                let arg_trees = args
+                    .iter()
+                    .map(|arg| self.convert_operand(arg))
+                    .collect::<Vec<_>>();
+                let return_type = callee.ty().ty();
+                let call_expr = Tree::new_call_expr(
+                    UNKNOWN_LOCATION,
+                    return_type,
+                    Tree::new_addr_expr(callee),
+                    &arg_trees,
+                );
+
+                match destination {
+                    Some((place, target)) => {
+                        let place = self.get_place(place);
+                        self.stmt_list.push(Tree::new_init_expr(place, call_expr));
+                        self.stmt_list
+                            .push(Tree::new_goto_expr(self.block_labels[target.as_usize()]));
+                    }
+                    None => {
+                        // Callee's return type is `!`: nothing in this block ever runs after
+                        // the call, so goto the unreachable sink instead of falling through
+                        // into the next block's label.
+                        self.stmt_list.push(call_expr);
+                        self.stmt_list
+                            .push(Tree::new_goto_expr(self.unreachable_label));
+                    }
+                }
+            }
+
+            // Minimal deallocation path for owning heap values (`Box<T>`): free the backing
+            // allocation. This doesn't run the pointee's own drop glue, which is out of scope
+            // until the rest of the Drop lowering exists.
+            Drop {
+                location, target, ..
+            } => {
+                if !place_ty(body, location).is_box() {
+                    unimplemented!("drop {:?}", terminator);
+                }
+
+                let place = self.get_place(location);
+                let free_fn = table.free_fn();
+                let free_call = Tree::new_call_expr(
+                    UNKNOWN_LOCATION,
+                    TreeIndex::VoidType.into(),
+                    Tree::new_addr_expr(free_fn),
+                    &[place],
+                );
+
+                self.stmt_list.push(free_call);
+                self.stmt_list
+                    .push(Tree::new_goto_expr(self.block_labels[target.as_usize()]));
+            }
+
             _ => unimplemented!("{:?}", terminator),
         }
     }
 
     fn finalize(mut self) {
-        let bind_expr = Tree::new_bind_expr(NULL_TREE, self.stmt_list.0, self.main_gcc_block);
+        let var_list = self
+            .vars
+            .iter()
+            .rev()
+            .fold(NULL_TREE, |chain, &var| Tree::new_tree_list(var, chain));
+
+        // Defines `unreachable_label` so a dangling goto to it (after a call that never
+        // returns) is valid, even though nothing ever reaches this point.
+        self.stmt_list.push(self.unreachable_label);
+        self.stmt_list.push(Tree::new_return_expr(self.res_decl));
+
+        let bind_expr = Tree::new_bind_expr(var_list, self.stmt_list.0, self.main_gcc_block);
         self.fn_decl.set_saved_tree(bind_expr);
 
         self.fn_decl.gimplify();
@@ -188,12 +615,12 @@ impl FunctionConversion {
     }
 }
 
-fn func_mir_to_gcc(name: Symbol, func_mir: &Body) {
-    let mut fn_conv = FunctionConversion::new(name, func_mir);
+fn func_mir_to_gcc(table: &mut FunctionTable, def_id: DefId, name: Symbol, func_mir: &Body) {
+    let mut fn_conv = FunctionConversion::new(table, def_id, name, func_mir);
 
     println!("name: {}", name);
     for (bb_idx, bb) in func_mir.basic_blocks().iter_enumerated() {
-        fn_conv.convert_basic_block(bb_idx, bb);
+        fn_conv.convert_basic_block(table, func_mir, bb_idx, bb);
     }
 
     println!();
@@ -203,11 +630,13 @@ fn func_mir_to_gcc(name: Symbol, func_mir: &Body) {
 
 pub fn mir2gimple<'tcx>(queries: &'tcx Queries<'tcx>) {
     queries.global_ctxt().unwrap().peek_mut().enter(|tcx| {
+        let mut table = FunctionTable::new(tcx);
+
         for &mir_key in tcx.mir_keys(LOCAL_CRATE) {
             // TODO: symbol_name?
             let name = tcx.item_name(mir_key);
             let mir = tcx.optimized_mir(mir_key);
-            func_mir_to_gcc(name, mir);
+            func_mir_to_gcc(&mut table, mir_key, name, mir);
         }
     });
 }